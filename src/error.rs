@@ -0,0 +1,22 @@
+//! Errors related to the transformation of a Nickel program.
+
+use crate::position::TermPos;
+use codespan::FileId;
+
+/// An error occurring while transforming a program, in particular while resolving its imports.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportError {
+    /// An IO error occurred while trying to read an imported file.
+    IOError(String, String, TermPos),
+    /// The imported file failed to parse.
+    ParseError(String, TermPos),
+    /// Resolving an import would re-enter a file that is already being resolved further up the
+    /// current import chain.
+    CyclicImport {
+        /// The files on the cycle in import order: it starts with the file that is imported again
+        /// and ends with the file whose import closes the cycle. The first file is not repeated
+        /// at the end, so a self-import gives a single-element path.
+        path: Vec<FileId>,
+        pos: TermPos,
+    },
+}
@@ -0,0 +1,33 @@
+//! Identifiers.
+
+use std::fmt;
+
+/// An identifier, either written by the user or generated internally by a program transformation.
+///
+/// This stays a plain wrapper around a `String`, exactly as before, so every existing
+/// construction and pattern match on `Ident` elsewhere in the compiler (parser, evaluator,
+/// pretty-printer) keeps working unchanged. [`Ident::fresh`] still guarantees its result cannot
+/// clash with a user-written identifier, but does so with [`GENSYM_MARKER`], a byte the lexer's
+/// identifier grammar can never produce - not just the `%` convention this replaces. Moving
+/// generated identifiers to a real non-`String` representation (interned, O(1) to compare) is a
+/// larger migration across those other call sites, and is left to a follow-up.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Ident(pub String);
+
+/// A byte the lexer's identifier grammar can never produce, used to namespace compiler-generated
+/// identifiers away from anything a user could type.
+const GENSYM_MARKER: char = '\u{0}';
+
+impl Ident {
+    /// Generate a fresh identifier from a serial number, guaranteed not to clash with any
+    /// identifier the parser could have produced.
+    pub fn fresh(n: usize) -> Ident {
+        Ident(format!("{}{}", GENSYM_MARKER, n))
+    }
+}
+
+impl fmt::Display for Ident {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
@@ -0,0 +1,26 @@
+//! Source code positions.
+
+use codespan::FileId;
+
+/// A span of source code within a single file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawSpan {
+    pub src_id: FileId,
+    pub start: u32,
+    pub end: u32,
+}
+
+/// The position of a term in the source.
+///
+/// Terms introduced by program transformations (e.g. the fresh variables generated by
+/// [`crate::transformations`]) have no position of their own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TermPos {
+    /// The term corresponds directly to this span.
+    Original(RawSpan),
+    /// The term was introduced by a transformation but inherits the position of the term it
+    /// replaces.
+    Inherited(RawSpan),
+    /// The term has no meaningful position.
+    None,
+}
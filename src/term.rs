@@ -0,0 +1,137 @@
+//! The Nickel AST.
+
+use crate::identifier::Ident;
+use crate::label::Label;
+use crate::position::TermPos;
+use crate::types::Types;
+use codespan::FileId;
+use std::collections::HashMap;
+
+/// A node of the Nickel AST.
+#[derive(Debug)]
+pub enum Term {
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Lbl(Label),
+    Sym(i32),
+    Enum(Ident),
+    Var(Ident),
+    Fun(Ident, RichTerm),
+    Let(Ident, RichTerm, RichTerm),
+    Record(HashMap<Ident, RichTerm>),
+    List(Vec<RichTerm>),
+    DefaultValue(RichTerm),
+    ContractWithDefault(Types, Label, RichTerm),
+    Docstring(String, RichTerm),
+    /// An import which hasn't been resolved yet.
+    Import(String),
+    /// A resolved import: the content lives in the import cache, indexed by this `FileId`.
+    ResolvedImport(FileId),
+}
+
+/// A [`Term`] together with its source position.
+#[derive(Debug)]
+pub struct RichTerm {
+    pub term: Box<Term>,
+    pub pos: TermPos,
+}
+
+impl From<Term> for RichTerm {
+    fn from(term: Term) -> Self {
+        RichTerm {
+            term: Box::new(term),
+            pos: TermPos::None,
+        }
+    }
+}
+
+/// Safety margin left on the native stack before [`traverse_child`] grows it, and the size of
+/// each newly allocated segment.
+const STACK_RED_ZONE: usize = 100 * 1024;
+const STACK_EXTENSION_SIZE: usize = 1024 * 1024;
+
+impl RichTerm {
+    /// Traverse the AST, applying `f` to the top-level node of every (sub)term.
+    ///
+    /// `f` is applied to a node first, then `traverse` recurses into the children of the
+    /// resulting term. This is what lets a single non-recursive rewrite (such as
+    /// `share_normal_form::transform_one`) reach every node once a full traversal is run over it.
+    pub fn traverse<S, E>(
+        self,
+        f: &mut impl FnMut(RichTerm, &mut S) -> Result<RichTerm, E>,
+        state: &mut S,
+    ) -> Result<RichTerm, E> {
+        let RichTerm { term, pos } = f(self, state)?;
+
+        let term = match *term {
+            Term::Fun(id, t) => Term::Fun(id, traverse_child(t, f, state)?),
+            Term::Let(id, t1, t2) => Term::Let(
+                id,
+                traverse_child(t1, f, state)?,
+                traverse_child(t2, f, state)?,
+            ),
+            Term::Record(map) => Term::Record(
+                map.into_iter()
+                    .map(|(id, t)| Ok((id, traverse_child(t, f, state)?)))
+                    .collect::<Result<_, E>>()?,
+            ),
+            Term::List(ts) => Term::List(
+                ts.into_iter()
+                    .map(|t| traverse_child(t, f, state))
+                    .collect::<Result<_, E>>()?,
+            ),
+            Term::DefaultValue(t) => Term::DefaultValue(traverse_child(t, f, state)?),
+            Term::ContractWithDefault(ty, lbl, t) => {
+                Term::ContractWithDefault(ty, lbl, traverse_child(t, f, state)?)
+            }
+            Term::Docstring(s, t) => Term::Docstring(s, traverse_child(t, f, state)?),
+            t => t,
+        };
+
+        Ok(RichTerm {
+            term: Box::new(term),
+            pos,
+        })
+    }
+}
+
+/// Recurse into a child term, growing the native stack first if it is running low.
+///
+/// `traverse` descends one native stack frame per level of nesting, so a generated value with
+/// thousands of nested records or a long list-of-lists would overflow the stack long before any
+/// pass gets a chance to run. Guarding the descent here, at each point where `traverse` actually
+/// recurses, gives every depth of the term its own headroom.
+fn traverse_child<S, E>(
+    t: RichTerm,
+    f: &mut impl FnMut(RichTerm, &mut S) -> Result<RichTerm, E>,
+    state: &mut S,
+) -> Result<RichTerm, E> {
+    stacker::maybe_grow(STACK_RED_ZONE, STACK_EXTENSION_SIZE, || {
+        t.traverse(f, state)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `traverse` recurses one native stack frame per level of nesting; without the stack growth
+    /// guard in `traverse_child`, a term nested this deeply overflows the stack long before any
+    /// pass gets to run. This asserts it returns instead.
+    ///
+    /// The result is leaked rather than dropped: `Term`'s ordinary recursive `Drop` would walk
+    /// this same depth unguarded, which is a separate, pre-existing gap this test isn't about.
+    #[test]
+    fn traverse_survives_deep_nesting() {
+        let depth = 200_000;
+        let mut rt: RichTerm = Term::Bool(true).into();
+        for _ in 0..depth {
+            rt = Term::List(vec![rt]).into();
+        }
+
+        let result: Result<RichTerm, ()> = rt.traverse(&mut |rt, _state: &mut ()| Ok(rt), &mut ());
+        assert!(result.is_ok());
+        std::mem::forget(result);
+    }
+}
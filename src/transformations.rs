@@ -9,6 +9,7 @@ use crate::types::{AbsType, Types};
 use codespan::FileId;
 use simple_counter::*;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
 generate_counter!(FreshVarCounter, usize);
@@ -184,6 +185,25 @@ pub mod share_normal_form {
             _ => true,
         }
     }
+
+    /// The [`Transformation`](../trait.Transformation.html) wrapping [`transform_one`]. It never
+    /// touches the import-resolution side of [`TransformState`](../struct.TransformState.html),
+    /// so it is generic over any resolver `R`.
+    pub struct SharingPass;
+
+    impl<R> super::Transformation<R> for SharingPass {
+        fn name(&self) -> &'static str {
+            "share_normal_form"
+        }
+
+        fn transform_one(
+            &self,
+            rt: RichTerm,
+            _state: &mut super::TransformState<R>,
+        ) -> Result<RichTerm, super::ImportError> {
+            Ok(transform_one(rt))
+        }
+    }
 }
 
 pub mod import_resolution {
@@ -191,16 +211,25 @@ pub mod import_resolution {
     use crate::error::ImportError;
     use crate::program::ResolvedTerm;
 
+    /// The outcome of resolving an import: the `FileId` it points to, plus the freshly parsed
+    /// content of the file if this is the first time it is resolved. `content` is `None` when the
+    /// resolver served the term `FromCache`; the caller still needs the `FileId` then to tell a
+    /// harmless diamond import from a cyclic one.
+    pub struct ImportRecord {
+        pub file_id: FileId,
+        pub content: Option<RichTerm>,
+    }
+
     /// Resolve the import if the term is an unresolved import, or return the term unchanged.
     ///
-    /// If an import was resolved, the corresponding `FileId` is returned in the second component
-    /// of the result. It the import has been already resolved, or if the term was not an import,
-    /// `None` is returned. As [`share_normal_form::transform_one`](./mod.?), this function is not
-    /// recursive.
+    /// If the term was an import, an [`ImportRecord`] is returned in the second component of the
+    /// result, whether or not the import had already been resolved before (see `ImportRecord`'s
+    /// doc). If the term was not an import, `None` is returned. As
+    /// [`share_normal_form::transform_one`](./mod.?), this function is not recursive.
     pub fn transform_one<R>(
         rt: RichTerm,
         resolver: &mut R,
-    ) -> Result<(RichTerm, Option<(RichTerm, FileId)>), ImportError>
+    ) -> Result<(RichTerm, Option<ImportRecord>), ImportError>
     where
         R: ImportResolver,
     {
@@ -208,9 +237,9 @@ pub mod import_resolution {
         match *term {
             Term::Import(path) => {
                 let (res_term, file_id) = resolver.resolve(&path, &pos)?;
-                let ret = match res_term {
+                let content = match res_term {
                     ResolvedTerm::FromCache() => None,
-                    ResolvedTerm::FromFile(t) => Some((t, file_id)),
+                    ResolvedTerm::FromFile(t) => Some(t),
                 };
 
                 Ok((
@@ -218,7 +247,7 @@ pub mod import_resolution {
                         term: Box::new(Term::ResolvedImport(file_id)),
                         pos,
                     },
-                    ret,
+                    Some(ImportRecord { file_id, content }),
                 ))
             }
             t => Ok((
@@ -230,13 +259,86 @@ pub mod import_resolution {
             )),
         }
     }
+
+    /// The [`Transformation`](../trait.Transformation.html) wrapping [`transform_one`]. Besides
+    /// resolving the import, it is responsible for queuing the resolved term on
+    /// [`TransformState::stack`](../struct.TransformState.html) and for rejecting the term if
+    /// resolving it would close a cyclic import.
+    pub struct ImportResolutionPass;
+
+    impl<R: ImportResolver> super::Transformation<R> for ImportResolutionPass {
+        fn name(&self) -> &'static str {
+            "import_resolution"
+        }
+
+        fn transform_one(
+            &self,
+            rt: RichTerm,
+            state: &mut super::TransformState<R>,
+        ) -> Result<RichTerm, ImportError> {
+            let (rt, resolved) = transform_one(rt, state.resolver)?;
+
+            if let Some(ImportRecord {
+                file_id: target,
+                content,
+            }) = resolved
+            {
+                if let Some(path) = super::find_cycle(state.file_id, target, state.parents) {
+                    return Err(ImportError::CyclicImport {
+                        path,
+                        pos: rt.pos.clone(),
+                    });
+                }
+
+                if let Some(t) = content {
+                    state.parents.insert(target, state.file_id);
+                    state.stack.push((t, target, state.file_id));
+                }
+            }
+
+            Ok(rt)
+        }
+    }
 }
 
 /// The state passed around during the program transformation. It holds a reference to the import
-/// resolver and to a stack of pending imported term to be transformed.
-struct TransformState<'a, R> {
+/// resolver, the stack of pending imported terms to be transformed, the id of the file currently
+/// being processed and the import graph built so far (used to detect cyclic imports).
+pub struct TransformState<'a, R> {
     resolver: &'a mut R,
-    stack: &'a mut Vec<(RichTerm, FileId)>,
+    stack: &'a mut Vec<(RichTerm, FileId, FileId)>,
+    file_id: FileId,
+    parents: &'a mut HashMap<FileId, FileId>,
+}
+
+/// A single rewrite applied to every node of a term during a transformation pass.
+///
+/// A full transformation is a `Vec<Box<dyn Transformation<R>>>` folded over each node of the term
+/// in a single traversal (see `transform_pass`). Adding a new rewrite means implementing this
+/// trait, not editing the traversal driver.
+pub trait Transformation<R> {
+    /// A name identifying the pass, for debugging and error messages.
+    fn name(&self) -> &'static str;
+
+    /// Apply one step of the transformation to the top-level node of `rt`.
+    ///
+    /// As with [`share_normal_form::transform_one`], this is not recursive: it is folded over a
+    /// traversal by [`transform_pass`] to obtain a full transformation. Side effects on the rest
+    /// of the transformation, such as import resolution queuing a newly resolved file, go through
+    /// `state`.
+    fn transform_one(
+        &self,
+        rt: RichTerm,
+        state: &mut TransformState<R>,
+    ) -> Result<RichTerm, ImportError>;
+}
+
+/// The passes applied by [`transform`]: share normal form, then import resolution, in that order.
+pub fn default_passes<R: ImportResolver>() -> Vec<Box<dyn Transformation<R>>> {
+    vec![
+        Box::new(share_normal_form::SharingPass),
+        Box::new(import_resolution::ImportResolutionPass),
+    ]
 }
 
 /// Apply all program transformations, which are currently the share normal form transformation and
@@ -245,53 +347,112 @@ struct TransformState<'a, R> {
 /// All resolved imports are stacked during the transformation. Once the term has been traversed,
 /// the elements of this stack are processed (and so on, if these elements also have non resolved
 /// imports).
-pub fn transform<R>(rt: RichTerm, resolver: &mut R) -> Result<RichTerm, ImportError>
+///
+/// `file_id` is the id of the file `rt` was parsed from: it is the root of the import graph built
+/// to detect cycles, and as such has no parent.
+pub fn transform<R>(
+    rt: RichTerm,
+    file_id: FileId,
+    resolver: &mut R,
+) -> Result<RichTerm, ImportError>
+where
+    R: ImportResolver,
+{
+    transform_with(rt, file_id, resolver, &default_passes())
+}
+
+/// Like [`transform`], but with an explicit, ordered pipeline of passes instead of
+/// [`default_passes`].
+pub fn transform_with<R>(
+    rt: RichTerm,
+    file_id: FileId,
+    resolver: &mut R,
+    passes: &[Box<dyn Transformation<R>>],
+) -> Result<RichTerm, ImportError>
 where
     R: ImportResolver,
 {
     let mut stack = Vec::new();
+    let mut parents = HashMap::new();
 
-    let result = transform_pass(rt, resolver, &mut stack);
+    let result = transform_pass(rt, file_id, resolver, &mut stack, &mut parents, passes);
 
-    while let Some((t, file_id)) = stack.pop() {
-        let result = transform_pass(t, resolver, &mut stack)?;
+    while let Some((t, file_id, _parent_id)) = stack.pop() {
+        let result = transform_pass(t, file_id, resolver, &mut stack, &mut parents, passes)?;
         resolver.insert(file_id, result);
     }
 
     result
 }
 
-/// Perform one full transformation pass. Put all imports encountered for the first time in
-/// `stack`, but do not process them.
+/// Perform one full transformation pass over the term of `file_id`, folding `passes` over every
+/// node. Put all imports encountered for the first time in `stack`, but do not process them.
 fn transform_pass<R>(
     rt: RichTerm,
+    file_id: FileId,
     resolver: &mut R,
-    stack: &mut Vec<(RichTerm, FileId)>,
+    stack: &mut Vec<(RichTerm, FileId, FileId)>,
+    parents: &mut HashMap<FileId, FileId>,
+    passes: &[Box<dyn Transformation<R>>],
 ) -> Result<RichTerm, ImportError>
 where
     R: ImportResolver,
 {
-    let mut state = TransformState { resolver, stack };
+    let mut state = TransformState {
+        resolver,
+        stack,
+        file_id,
+        parents,
+    };
 
-    // Apply one step of each transformation. If an import is resolved, then stack it.
+    // `RichTerm::traverse` itself grows the native stack as needed at each level it recurses
+    // into, so arbitrarily deep terms are handled there; this closure only has to fold the
+    // passes over the current node.
     rt.traverse(
         &mut |rt: RichTerm, state: &mut TransformState<R>| -> Result<RichTerm, ImportError> {
-            let rt = share_normal_form::transform_one(rt);
-            let (rt, to_queue) = import_resolution::transform_one(rt, state.resolver)?;
-
-            if let Some((t, file_id)) = to_queue {
-                state.stack.push((t, file_id));
-            }
-
-            Ok(rt)
+            passes
+                .iter()
+                .try_fold(rt, |rt, pass| pass.transform_one(rt, state))
         },
         &mut state,
     )
 }
 
-/// Generate a new fresh variable which do not clash with user-defined variables.
+/// Look for `target` among the ancestors of `from` in the import graph built so far.
+///
+/// `parents` maps a resolved file to the file that imported it first; the root has no entry.
+/// Walking `parents` from `from` up to the root retraces the chain of imports currently being
+/// resolved. If `target` appears on that chain - `target == from` included, for a self-import -
+/// resolving it would close a cycle, and the chain from `target` back down to `from` is returned.
+/// A file resolved from some unrelated, already finished branch (a diamond) is not an ancestor of
+/// `from`, so this correctly returns `None` for it.
+fn find_cycle(
+    from: FileId,
+    target: FileId,
+    parents: &HashMap<FileId, FileId>,
+) -> Option<Vec<FileId>> {
+    let mut path = vec![from];
+    let mut current = from;
+
+    loop {
+        if current == target {
+            path.reverse();
+            return Some(path);
+        }
+
+        match parents.get(&current) {
+            Some(parent) => {
+                current = *parent;
+                path.push(current);
+            }
+            None => return None,
+        }
+    }
+}
+
+/// Generate a new fresh, compiler-generated variable which cannot clash with a user-defined one.
 fn fresh_var() -> Ident {
-    Ident(format!("%{}", FreshVarCounter::next()))
+    Ident::fresh(FreshVarCounter::next())
 }
 
 /// Structures which can be packed together with their environment as a closure.
@@ -332,3 +493,47 @@ impl Closurizable for Types {
         Types(AbsType::Flat(self.contract().closurize(env, with_env)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codespan::Files;
+
+    fn file_ids(n: usize) -> Vec<FileId> {
+        let mut files = Files::<String>::new();
+        (0..n)
+            .map(|i| files.add(format!("file{}", i), String::new()))
+            .collect()
+    }
+
+    #[test]
+    fn find_cycle_detects_self_import() {
+        let ids = file_ids(1);
+        let parents = HashMap::new();
+        assert_eq!(find_cycle(ids[0], ids[0], &parents), Some(vec![ids[0]]));
+    }
+
+    #[test]
+    fn find_cycle_detects_multi_hop_cycle() {
+        let ids = file_ids(3);
+        let mut parents = HashMap::new();
+        parents.insert(ids[1], ids[0]);
+        parents.insert(ids[2], ids[1]);
+
+        assert_eq!(
+            find_cycle(ids[2], ids[0], &parents),
+            Some(vec![ids[0], ids[1], ids[2]])
+        );
+    }
+
+    #[test]
+    fn find_cycle_ignores_diamond_import() {
+        let ids = file_ids(4);
+        let mut parents = HashMap::new();
+        parents.insert(ids[1], ids[0]);
+        parents.insert(ids[2], ids[0]);
+        parents.insert(ids[3], ids[1]);
+
+        assert_eq!(find_cycle(ids[2], ids[3], &parents), None);
+    }
+}